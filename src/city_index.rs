@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+
+use crate::{CountryInfo, State};
+
+/// A city returned from `CityIndex::lookup`, carrying its owning state and
+/// country alongside its validated coordinates.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct CityHit {
+    pub(crate) city_id: u32,
+    pub(crate) city_name: String,
+    pub(crate) state_name: String,
+    pub(crate) country_code: String,
+    pub(crate) latitude: Option<f64>,
+    pub(crate) longitude: Option<f64>,
+}
+
+/// ASCII-normalized, in-memory city name index. Keys are built by
+/// transliterating each city name to ASCII (unidecode-style) and
+/// lowercasing it, so a plain-ASCII query can still match accented names
+/// (e.g. "sao paulo" matches "São Paulo").
+pub(crate) struct CityIndex {
+    by_key: HashMap<String, Vec<CityHit>>,
+    sorted_keys: Vec<String>,
+}
+
+/// Lowercases `name` and transliterates non-ASCII characters to their
+/// closest ASCII equivalent, so accented and plain-ASCII spellings
+/// normalize to the same key.
+fn normalize(name: &str) -> String {
+    unidecode::unidecode(name).to_lowercase()
+}
+
+impl CityIndex {
+    /// Builds the index from grouped states, keyed by normalized city name.
+    pub(crate) fn build(
+        by_country: &HashMap<u32, Vec<State>>,
+        country_map: &HashMap<u32, CountryInfo>,
+    ) -> Self {
+        let mut by_key: HashMap<String, Vec<CityHit>> = HashMap::new();
+
+        for (country_id, states) in by_country {
+            let country_code = country_map
+                .get(country_id)
+                .map(|c| c.iso2.as_str())
+                .unwrap_or("XX");
+
+            for state in states {
+                for city in &state.cities {
+                    let key = normalize(&city.name);
+                    by_key.entry(key).or_default().push(CityHit {
+                        city_id: city.id,
+                        city_name: city.name.clone(),
+                        state_name: state.name.clone(),
+                        country_code: country_code.to_string(),
+                        latitude: city.parsed_latitude,
+                        longitude: city.parsed_longitude,
+                    });
+                }
+            }
+        }
+
+        let mut sorted_keys: Vec<String> = by_key.keys().cloned().collect();
+        sorted_keys.sort();
+
+        Self {
+            by_key,
+            sorted_keys,
+        }
+    }
+
+    /// Looks up `name`, trying an exact normalized match first and falling
+    /// back to a prefix search over the sorted key list. Results are
+    /// filtered to `country_code` when given.
+    pub(crate) fn lookup(&self, name: &str, country_code: Option<&str>) -> Vec<CityHit> {
+        let key = normalize(name);
+
+        let mut hits = self
+            .by_key
+            .get(&key)
+            .cloned()
+            .unwrap_or_else(|| self.prefix_matches(&key));
+
+        if let Some(code) = country_code {
+            hits.retain(|hit| hit.country_code.eq_ignore_ascii_case(code));
+        }
+
+        hits
+    }
+
+    fn prefix_matches(&self, prefix: &str) -> Vec<CityHit> {
+        let start = self
+            .sorted_keys
+            .partition_point(|key| key.as_str() < prefix);
+
+        let mut hits = Vec::new();
+        for key in &self.sorted_keys[start..] {
+            if !key.starts_with(prefix) {
+                break;
+            }
+            hits.extend(self.by_key[key].iter().cloned());
+        }
+        hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::City;
+
+    fn city(id: u32, name: &str, lat: f64, lon: f64) -> City {
+        City {
+            id,
+            name: name.to_string(),
+            latitude: None,
+            longitude: None,
+            parsed_latitude: Some(lat),
+            parsed_longitude: Some(lon),
+        }
+    }
+
+    fn state(id: u32, country_id: u32, name: &str, cities: Vec<City>) -> State {
+        State {
+            id,
+            country_id,
+            name: name.to_string(),
+            state_code: None,
+            iso_3166_2: None,
+            latitude: None,
+            longitude: None,
+            parsed_latitude: None,
+            parsed_longitude: None,
+            cities,
+        }
+    }
+
+    fn country_info(iso2: &str) -> CountryInfo {
+        CountryInfo {
+            iso2: iso2.to_string(),
+            iso3: None,
+            numeric_code: None,
+        }
+    }
+
+    #[test]
+    fn test_lookup_normalizes_accents() {
+        let mut by_country = HashMap::new();
+        by_country.insert(
+            1,
+            vec![state(
+                1,
+                1,
+                "Sao Paulo State",
+                vec![city(1, "São Paulo", -23.5505, -46.6333)],
+            )],
+        );
+        let mut country_map = HashMap::new();
+        country_map.insert(1, country_info("BR"));
+
+        let index = CityIndex::build(&by_country, &country_map);
+
+        let hits = index.lookup("sao paulo", None);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].city_name, "São Paulo");
+        assert_eq!(hits[0].country_code, "BR");
+    }
+
+    #[test]
+    fn test_lookup_filters_by_country() {
+        let mut by_country = HashMap::new();
+        by_country.insert(
+            1,
+            vec![state(
+                1,
+                1,
+                "State A",
+                vec![city(1, "Springfield", 1.0, 1.0)],
+            )],
+        );
+        by_country.insert(
+            2,
+            vec![state(
+                2,
+                2,
+                "State B",
+                vec![city(2, "Springfield", 2.0, 2.0)],
+            )],
+        );
+        let mut country_map = HashMap::new();
+        country_map.insert(1, country_info("US"));
+        country_map.insert(2, country_info("CA"));
+
+        let index = CityIndex::build(&by_country, &country_map);
+
+        let hits = index.lookup("springfield", Some("CA"));
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].country_code, "CA");
+    }
+
+    #[test]
+    fn test_prefix_search_falls_back_when_no_exact_match() {
+        let mut by_country = HashMap::new();
+        by_country.insert(
+            1,
+            vec![state(
+                1,
+                1,
+                "Munich State",
+                vec![city(1, "München", 48.1351, 11.582)],
+            )],
+        );
+        let mut country_map = HashMap::new();
+        country_map.insert(1, country_info("DE"));
+
+        let index = CityIndex::build(&by_country, &country_map);
+
+        let hits = index.lookup("munch", None);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].city_name, "München");
+    }
+}
@@ -1,5 +1,15 @@
+mod city_index;
+
+use rayon::prelude::*;
+use serde::de::Deserializer;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs, path::Path};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{BufRead, BufReader},
+    path::Path,
+    time::Instant,
+};
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "snake_case")]
@@ -8,39 +18,86 @@ struct Country {
 
     #[serde(rename = "iso2")]
     code: String,
+
+    #[serde(default)]
+    iso3: Option<String>,
+
+    #[serde(default)]
+    numeric_code: Option<String>,
+}
+
+/// The ISO 3166-1 code set for a country, carried alongside per-country
+/// output so consumers can join against datasets keyed on alpha-2,
+/// alpha-3, or numeric codes.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) struct CountryInfo {
+    pub(crate) iso2: String,
+    pub(crate) iso3: Option<String>,
+    pub(crate) numeric_code: Option<String>,
+}
+
+impl CountryInfo {
+    /// Builds the ISO 3166-2 subdivision code (`{iso2}-{state_code}`) for a
+    /// state of this country, when the state carries a `state_code`.
+    fn subdivision_code(&self, state_code: &Option<String>) -> Option<String> {
+        let state_code = state_code.as_ref()?;
+        Some(format!("{}-{}", self.iso2, state_code))
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "snake_case")]
-struct City {
-    id: u32,
-    name: String,
+pub(crate) struct City {
+    pub(crate) id: u32,
+    pub(crate) name: String,
 
     #[serde(default)]
-    latitude: Option<String>,
+    pub(crate) latitude: Option<String>,
 
     #[serde(default)]
-    longitude: Option<String>,
+    pub(crate) longitude: Option<String>,
+
+    /// Numeric, range-checked latitude, filled in by `validate_coordinates`.
+    #[serde(skip)]
+    pub(crate) parsed_latitude: Option<f64>,
+
+    /// Numeric, range-checked longitude, filled in by `validate_coordinates`.
+    #[serde(skip)]
+    pub(crate) parsed_longitude: Option<f64>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "snake_case")]
-struct State {
-    id: u32,
-    country_id: u32,
-    name: String,
+pub(crate) struct State {
+    pub(crate) id: u32,
+    pub(crate) country_id: u32,
+    pub(crate) name: String,
 
     #[serde(default)]
-    state_code: Option<String>,
+    pub(crate) state_code: Option<String>,
+
+    /// ISO 3166-2 subdivision code (`{iso2}-{state_code}`), filled in by
+    /// `write_country_files` from the owning country's ISO2 code.
+    #[serde(default, skip_deserializing)]
+    pub(crate) iso_3166_2: Option<String>,
 
     #[serde(default)]
-    latitude: Option<String>,
+    pub(crate) latitude: Option<String>,
 
     #[serde(default)]
-    longitude: Option<String>,
+    pub(crate) longitude: Option<String>,
+
+    /// Numeric, range-checked latitude, filled in by `validate_coordinates`.
+    #[serde(skip)]
+    pub(crate) parsed_latitude: Option<f64>,
+
+    /// Numeric, range-checked longitude, filled in by `validate_coordinates`.
+    #[serde(skip)]
+    pub(crate) parsed_longitude: Option<f64>,
 
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
-    cities: Vec<City>,
+    pub(crate) cities: Vec<City>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -53,6 +110,18 @@ enum ProcessingError {
 
     #[error("File too large: {size} bytes (max: {max_size})")]
     FileTooLarge { size: u64, max_size: u64 },
+
+    #[error(
+        "coordinate {raw_value:?} for entity {entity_id} (country {country_id}) is out of range"
+    )]
+    CoordinateOutOfRange {
+        country_id: u32,
+        entity_id: u32,
+        raw_value: String,
+    },
+
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv::Error),
 }
 
 type Result<T> = std::result::Result<T, ProcessingError>;
@@ -70,7 +139,7 @@ fn validate_file_size(path: &Path) -> Result<()> {
     Ok(())
 }
 
-fn load_countries(raw_dir: &Path) -> Result<HashMap<u32, String>> {
+fn load_countries(raw_dir: &Path) -> Result<HashMap<u32, CountryInfo>> {
     let countries_path = raw_dir.join("countries.json");
     validate_file_size(&countries_path)?;
 
@@ -80,78 +149,693 @@ fn load_countries(raw_dir: &Path) -> Result<HashMap<u32, String>> {
 
     let mut country_map = HashMap::new();
     for country in countries {
-        country_map.insert(country.id, country.code);
+        country_map.insert(
+            country.id,
+            CountryInfo {
+                iso2: country.code,
+                iso3: country.iso3,
+                numeric_code: country.numeric_code,
+            },
+        );
     }
 
     println!("✅ Loaded {} countries", country_map.len());
     Ok(country_map)
 }
 
-fn load_states(raw_dir: &Path) -> Result<Vec<State>> {
-    let states_path = raw_dir.join("states+cities.json");
-    validate_file_size(&states_path)?;
+/// `SeqAccess` visitor that dispatches each `State` to `processor` as it is
+/// parsed, instead of collecting them into a `Vec<State>` first.
+struct StateSeqVisitor<F: FnMut(State)> {
+    processor: F,
+    count: usize,
+}
 
-    println!("📊 Loading states and cities from {states_path:?}");
-    let states_data = fs::read_to_string(&states_path)?;
-    let states: Vec<State> = serde_json::from_str(&states_data)?;
+impl<'de, F: FnMut(State)> serde::de::Visitor<'de> for StateSeqVisitor<F> {
+    type Value = usize;
 
-    println!("✅ Loaded {} states", states.len());
-    Ok(states)
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a JSON array of state objects")
+    }
+
+    fn visit_seq<A>(mut self, mut seq: A) -> std::result::Result<Self::Value, A::Error>
+    where
+        A: serde::de::SeqAccess<'de>,
+    {
+        while let Some(state) = seq.next_element::<State>()? {
+            (self.processor)(state);
+            self.count += 1;
+        }
+        Ok(self.count)
+    }
 }
 
-fn group_states_by_country(states: Vec<State>) -> HashMap<u32, Vec<State>> {
-    let mut by_country: HashMap<u32, Vec<State>> = HashMap::new();
-    for state in states {
-        by_country.entry(state.country_id).or_default().push(state);
+fn insert_state(by_country: &mut HashMap<u32, Vec<State>>, state: State) {
+    by_country.entry(state.country_id).or_default().push(state);
+}
+
+/// Streams `states+cities.json` (or, if the file has a `.jsonl` extension, a
+/// JSON Lines variant with one `State` per line) and hands each parsed
+/// `State` to `processor` as it is read, so the full dataset is never held
+/// in memory as a single `Vec<State>`.
+fn load_states<F>(raw_dir: &Path, processor: F) -> Result<usize>
+where
+    F: FnMut(State),
+{
+    let states_path = locate_states_file(raw_dir)?;
+
+    println!("📊 Streaming states and cities from {states_path:?}");
+
+    let file = fs::File::open(&states_path)?;
+    let reader = BufReader::new(file);
+
+    let count = if states_path.extension().and_then(|ext| ext.to_str()) == Some("jsonl") {
+        load_states_jsonl(reader, processor)?
+    } else {
+        let mut deserializer = serde_json::Deserializer::from_reader(reader);
+        deserializer.deserialize_seq(StateSeqVisitor {
+            processor,
+            count: 0,
+        })?
+    };
+
+    println!("✅ Loaded {count} states");
+    Ok(count)
+}
+
+/// Picks `states+cities.jsonl` when present, falling back to the default
+/// `states+cities.json` array file.
+fn locate_states_file(raw_dir: &Path) -> Result<std::path::PathBuf> {
+    let jsonl_path = raw_dir.join("states+cities.jsonl");
+    if jsonl_path.exists() {
+        return Ok(jsonl_path);
+    }
+    Ok(raw_dir.join("states+cities.json"))
+}
+
+fn load_states_jsonl<R, F>(reader: BufReader<R>, mut processor: F) -> Result<usize>
+where
+    R: std::io::Read,
+    F: FnMut(State),
+{
+    let mut count = 0;
+    let mut line = String::new();
+    let mut reader = reader;
+
+    loop {
+        line.clear();
+        let bytes_read = reader.read_line(&mut line)?;
+        if bytes_read == 0 {
+            break;
+        }
+
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+
+        let state: State = serde_json::from_str(trimmed)?;
+        processor(state);
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+const LATITUDE_RANGE: std::ops::RangeInclusive<f64> = -90.0..=90.0;
+const LONGITUDE_RANGE: std::ops::RangeInclusive<f64> = -180.0..=180.0;
+
+/// Whether an out-of-range coordinate should only be recorded in the
+/// `CoordinateReport` (`Warn`, the default) or abort processing (`Error`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutOfRangePolicy {
+    Warn,
+    Error,
+}
+
+impl OutOfRangePolicy {
+    fn from_flag(value: &str) -> Option<Self> {
+        match value {
+            "warn" => Some(OutOfRangePolicy::Warn),
+            "error" => Some(OutOfRangePolicy::Error),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CoordinateAxis {
+    Latitude,
+    Longitude,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CoordinateIssueKind {
+    ParseFailed,
+    OutOfRange,
+}
+
+#[derive(Debug, Clone)]
+struct CoordinateIssue {
+    country_id: u32,
+    entity_id: u32,
+    axis: CoordinateAxis,
+    raw_value: String,
+    kind: CoordinateIssueKind,
+}
+
+/// Rows whose latitude/longitude failed to parse or fell outside the valid
+/// range, collected by `validate_coordinates`.
+#[derive(Debug, Default)]
+struct CoordinateReport {
+    issues: Vec<CoordinateIssue>,
+}
+
+impl CoordinateReport {
+    fn parse_failures(&self) -> usize {
+        self.issues
+            .iter()
+            .filter(|issue| issue.kind == CoordinateIssueKind::ParseFailed)
+            .count()
+    }
+
+    fn out_of_range(&self) -> usize {
+        self.issues
+            .iter()
+            .filter(|issue| issue.kind == CoordinateIssueKind::OutOfRange)
+            .count()
+    }
+
+    /// Logs one line per collected issue, identifying the offending country,
+    /// entity and raw value so bad source rows can be tracked down.
+    fn log_issues(&self) {
+        for issue in &self.issues {
+            let what = match issue.kind {
+                CoordinateIssueKind::ParseFailed => "failed to parse",
+                CoordinateIssueKind::OutOfRange => "out of range",
+            };
+            eprintln!(
+                "⚠️  country {} entity {}: {:?} {:?} {what}",
+                issue.country_id, issue.entity_id, issue.axis, issue.raw_value
+            );
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn validate_coordinate(
+    raw: &Option<String>,
+    range: std::ops::RangeInclusive<f64>,
+    axis: CoordinateAxis,
+    country_id: u32,
+    entity_id: u32,
+    policy: OutOfRangePolicy,
+    report: &mut CoordinateReport,
+) -> Result<Option<f64>> {
+    let Some(raw_value) = raw else {
+        return Ok(None);
+    };
+
+    let Ok(value) = raw_value.trim().parse::<f64>() else {
+        report.issues.push(CoordinateIssue {
+            country_id,
+            entity_id,
+            axis,
+            raw_value: raw_value.clone(),
+            kind: CoordinateIssueKind::ParseFailed,
+        });
+        return Ok(None);
+    };
+
+    if !range.contains(&value) {
+        report.issues.push(CoordinateIssue {
+            country_id,
+            entity_id,
+            axis,
+            raw_value: raw_value.clone(),
+            kind: CoordinateIssueKind::OutOfRange,
+        });
+
+        if policy == OutOfRangePolicy::Error {
+            return Err(ProcessingError::CoordinateOutOfRange {
+                country_id,
+                entity_id,
+                raw_value: raw_value.clone(),
+            });
+        }
+        return Ok(None);
+    }
+
+    Ok(Some(value))
+}
+
+/// Parses and range-validates every state's and city's latitude/longitude,
+/// filling in `parsed_latitude`/`parsed_longitude` in place and returning a
+/// report of rows that failed to parse or fell out of range. Out-of-range
+/// values are soft-warned (recorded, coordinate dropped) or hard-errored
+/// depending on `policy`.
+fn validate_coordinates(
+    by_country: &mut HashMap<u32, Vec<State>>,
+    policy: OutOfRangePolicy,
+) -> Result<CoordinateReport> {
+    let mut report = CoordinateReport::default();
+
+    for (&country_id, states) in by_country.iter_mut() {
+        for state in states.iter_mut() {
+            state.parsed_latitude = validate_coordinate(
+                &state.latitude,
+                LATITUDE_RANGE,
+                CoordinateAxis::Latitude,
+                country_id,
+                state.id,
+                policy,
+                &mut report,
+            )?;
+            state.parsed_longitude = validate_coordinate(
+                &state.longitude,
+                LONGITUDE_RANGE,
+                CoordinateAxis::Longitude,
+                country_id,
+                state.id,
+                policy,
+                &mut report,
+            )?;
+
+            for city in state.cities.iter_mut() {
+                city.parsed_latitude = validate_coordinate(
+                    &city.latitude,
+                    LATITUDE_RANGE,
+                    CoordinateAxis::Latitude,
+                    country_id,
+                    city.id,
+                    policy,
+                    &mut report,
+                )?;
+                city.parsed_longitude = validate_coordinate(
+                    &city.longitude,
+                    LONGITUDE_RANGE,
+                    CoordinateAxis::Longitude,
+                    country_id,
+                    city.id,
+                    policy,
+                    &mut report,
+                )?;
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Selects how per-country files are serialized by `write_country_files`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    /// A pretty-printed JSON `CountryFile`: the country's ISO 3166-1 codes
+    /// alongside its nested states.
+    Json,
+    /// A GeoJSON `FeatureCollection` of city/state points.
+    GeoJson,
+}
+
+impl OutputFormat {
+    fn from_flag(value: &str) -> Option<Self> {
+        match value {
+            "json" => Some(OutputFormat::Json),
+            "geojson" => Some(OutputFormat::GeoJson),
+            _ => None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Json => "json",
+            OutputFormat::GeoJson => "geojson",
+        }
+    }
+}
+
+/// The `OutputFormat::Json` per-country file: the ISO 3166-1 code set for
+/// the country alongside its nested `Vec<State>`.
+#[derive(Debug, Serialize)]
+struct CountryFile<'a> {
+    country: Option<CountryInfo>,
+    states: &'a [State],
+}
+
+#[derive(Debug, Serialize)]
+struct GeoJsonFeatureCollection {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    /// ISO 3166-1 code set for the country this collection covers, carried
+    /// as a GeoJSON foreign member alongside the standard `type`/`features`.
+    country: Option<CountryInfo>,
+    features: Vec<GeoJsonFeature>,
+}
+
+#[derive(Debug, Serialize)]
+struct GeoJsonFeature {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    geometry: GeoJsonGeometry,
+    properties: serde_json::Value,
+}
+
+#[derive(Debug, Serialize)]
+struct GeoJsonGeometry {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    coordinates: [f64; 2],
+}
+
+fn point_feature(lon: f64, lat: f64, properties: serde_json::Value) -> GeoJsonFeature {
+    GeoJsonFeature {
+        kind: "Feature",
+        geometry: GeoJsonGeometry {
+            kind: "Point",
+            coordinates: [lon, lat],
+        },
+        properties,
+    }
+}
+
+/// Builds the GeoJSON features for a single state: one `Point` per city
+/// with validated coordinates, falling back to a `Point` for the state
+/// itself (if it carries validated coordinates) only when it has no
+/// cities. Cities/states whose coordinates failed to parse or fell out of
+/// range (see `validate_coordinates`) are skipped.
+fn state_to_features(state: &State) -> Vec<GeoJsonFeature> {
+    let mut features = Vec::new();
+
+    if state.cities.is_empty() {
+        if let (Some(lat), Some(lon)) = (state.parsed_latitude, state.parsed_longitude) {
+            features.push(point_feature(
+                lon,
+                lat,
+                serde_json::json!({
+                    "id": state.id,
+                    "name": state.name,
+                }),
+            ));
+        }
+    }
+
+    for city in &state.cities {
+        if let (Some(lat), Some(lon)) = (city.parsed_latitude, city.parsed_longitude) {
+            features.push(point_feature(
+                lon,
+                lat,
+                serde_json::json!({
+                    "id": city.id,
+                    "name": city.name,
+                    "state": state.name,
+                }),
+            ));
+        }
+    }
+
+    features
+}
+
+/// Outcome of `write_country_files`: how many files landed, how many
+/// countries were skipped due to an error, and what those errors were.
+#[derive(Debug, Default)]
+struct WriteSummary {
+    files_written: usize,
+    countries_skipped: Vec<(u32, ProcessingError)>,
+}
+
+fn write_one_country(
+    country_id: u32,
+    states: &mut [State],
+    info: Option<&CountryInfo>,
+    out_dir: &Path,
+    format: OutputFormat,
+    use_iso3_filename: bool,
+) -> Result<String> {
+    let iso2 = info.map(|c| c.iso2.as_str()).unwrap_or("XX");
+
+    for state in states.iter_mut() {
+        state.iso_3166_2 = info.and_then(|c| c.subdivision_code(&state.state_code));
     }
-    by_country
+
+    let filename = if use_iso3_filename {
+        match info.and_then(|c| c.iso3.as_deref()) {
+            Some(iso3) => format!("{iso3}.{}", format.extension()),
+            // No iso3 (or no CountryInfo at all) to key the filename on;
+            // fall back to the country_id-qualified scheme so distinct
+            // unmapped countries don't collide on a bare "XX" filename.
+            None => format!("{country_id}_{iso2}.{}", format.extension()),
+        }
+    } else {
+        format!("{country_id}_{iso2}.{}", format.extension())
+    };
+    let out_path = out_dir.join(&filename);
+
+    let serialized = match format {
+        OutputFormat::Json => {
+            let file = CountryFile {
+                country: info.cloned(),
+                states,
+            };
+            serde_json::to_string_pretty(&file)?
+        }
+        OutputFormat::GeoJson => {
+            let features = states.iter().flat_map(state_to_features).collect();
+            let collection = GeoJsonFeatureCollection {
+                kind: "FeatureCollection",
+                country: info.cloned(),
+                features,
+            };
+            serde_json::to_string_pretty(&collection)?
+        }
+    };
+    fs::write(&out_path, serialized)?;
+
+    Ok(filename)
 }
 
+/// Writes each country's file in parallel (via rayon), recording per-country
+/// errors instead of aborting the whole run.
 fn write_country_files(
-    by_country: HashMap<u32, Vec<State>>,
-    country_map: &HashMap<u32, String>,
+    mut by_country: HashMap<u32, Vec<State>>,
+    country_map: &HashMap<u32, CountryInfo>,
     out_dir: &Path,
-) -> Result<()> {
+    format: OutputFormat,
+    use_iso3_filename: bool,
+) -> Result<WriteSummary> {
     let total_countries = by_country.len();
     println!("📝 Writing {total_countries} country files...");
 
-    for (i, (country_id, states)) in by_country.iter().enumerate() {
+    let results: Vec<(u32, usize, Result<String>)> = by_country
+        .par_iter_mut()
+        .map(|(&country_id, states)| {
+            let info = country_map.get(&country_id);
+            let result =
+                write_one_country(country_id, states, info, out_dir, format, use_iso3_filename);
+            (country_id, states.len(), result)
+        })
+        .collect();
+
+    let mut summary = WriteSummary::default();
+    for (country_id, state_count, result) in results {
+        match result {
+            Ok(filename) => {
+                summary.files_written += 1;
+                println!("🔄 Wrote {filename} with {state_count} states");
+            }
+            Err(err) => {
+                eprintln!("⚠️  Skipping country {country_id}: {err}");
+                summary.countries_skipped.push((country_id, err));
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+/// A single flattened row of the `cities.csv` export: one row per city,
+/// carrying its owning state and country alongside validated coordinates.
+#[derive(Debug, Serialize)]
+struct CityCsvRow {
+    country_code: String,
+    state_name: String,
+    state_code: String,
+    city_id: u32,
+    city_name: String,
+    latitude: String,
+    longitude: String,
+}
+
+fn state_to_csv_rows<'a>(
+    state: &'a State,
+    country_code: &'a str,
+) -> impl Iterator<Item = CityCsvRow> + 'a {
+    let state_name = state.name.clone();
+    let state_code = state.state_code.clone().unwrap_or_default();
+    let country_code = country_code.to_string();
+
+    state.cities.iter().map(move |city| CityCsvRow {
+        country_code: country_code.clone(),
+        state_name: state_name.clone(),
+        state_code: state_code.clone(),
+        city_id: city.id,
+        city_name: city.name.clone(),
+        latitude: city
+            .parsed_latitude
+            .map(|value| value.to_string())
+            .unwrap_or_default(),
+        longitude: city
+            .parsed_longitude
+            .map(|value| value.to_string())
+            .unwrap_or_default(),
+    })
+}
+
+/// Streams a flattened `cities.csv` (one row per city) directly from the
+/// grouped states, optionally also writing a `{country_id}_{code}.csv` per
+/// country, without building an intermediate in-memory table.
+fn write_csv_export(
+    by_country: &HashMap<u32, Vec<State>>,
+    country_map: &HashMap<u32, CountryInfo>,
+    out_dir: &Path,
+    per_country: bool,
+) -> Result<()> {
+    let combined_path = out_dir.join("cities.csv");
+    let mut combined_writer = csv::Writer::from_path(&combined_path)?;
+
+    for (country_id, states) in by_country {
         let code = country_map
             .get(country_id)
-            .map(String::as_str)
+            .map(|c| c.iso2.as_str())
             .unwrap_or("XX");
-        let filename = format!("{country_id}_{code}.json");
-        let out_path = out_dir.join(&filename);
 
-        let json = serde_json::to_string_pretty(states)?;
-        fs::write(&out_path, json)?;
+        let mut country_writer = if per_country {
+            let path = out_dir.join(format!("{country_id}_{code}.csv"));
+            Some(csv::Writer::from_path(path)?)
+        } else {
+            None
+        };
 
-        let progress = (i + 1) * 100 / total_countries;
-        println!(
-            "🔄 [{progress:3}%] Wrote {filename} with {} states",
-            states.len()
-        );
+        for state in states {
+            for row in state_to_csv_rows(state, code) {
+                combined_writer.serialize(&row)?;
+                if let Some(writer) = country_writer.as_mut() {
+                    writer.serialize(&row)?;
+                }
+            }
+        }
+
+        if let Some(mut writer) = country_writer {
+            writer.flush()?;
+        }
     }
 
+    combined_writer.flush()?;
+    println!("📄 Wrote flattened city table to {combined_path:?}");
     Ok(())
 }
 
+/// Reads `--format <json|geojson>` out of the CLI args, defaulting to
+/// `OutputFormat::Json` when the flag is absent.
+fn parse_format_flag(args: &[String]) -> OutputFormat {
+    args.iter()
+        .position(|arg| arg == "--format")
+        .and_then(|i| args.get(i + 1))
+        .and_then(|value| OutputFormat::from_flag(value))
+        .unwrap_or(OutputFormat::Json)
+}
+
+/// Reads `--on-out-of-range <warn|error>` out of the CLI args, defaulting
+/// to `OutOfRangePolicy::Warn` when the flag is absent.
+fn parse_out_of_range_flag(args: &[String]) -> OutOfRangePolicy {
+    args.iter()
+        .position(|arg| arg == "--on-out-of-range")
+        .and_then(|i| args.get(i + 1))
+        .and_then(|value| OutOfRangePolicy::from_flag(value))
+        .unwrap_or(OutOfRangePolicy::Warn)
+}
+
 fn main() -> Result<()> {
     println!("🌍 City Coordinates Lookup - Data Processor");
     println!("{}", "=".repeat(50));
 
+    let args: Vec<String> = std::env::args().collect();
+    let format = parse_format_flag(&args);
+    let out_of_range_policy = parse_out_of_range_flag(&args);
+    let csv_enabled = args.iter().any(|arg| arg == "--csv");
+    let csv_per_country = args.iter().any(|arg| arg == "--csv-per-country");
+    let use_iso3_filename = args.iter().any(|arg| arg == "--iso3-filenames");
+    let lookup_query = args
+        .iter()
+        .position(|arg| arg == "--lookup")
+        .and_then(|i| args.get(i + 1));
+    let lookup_country = args
+        .iter()
+        .position(|arg| arg == "--country")
+        .and_then(|i| args.get(i + 1));
+
     // Setup directories
     let raw_dir = Path::new("data").join("raw");
     let out_dir = Path::new("data").join("generated").join("per-country");
+    let csv_dir = Path::new("data").join("generated").join("csv");
 
     fs::create_dir_all(&out_dir)?;
 
+    let total_start = Instant::now();
+
     // Load and process data
+    let load_start = Instant::now();
     let country_map = load_countries(&raw_dir)?;
-    let states = load_states(&raw_dir)?;
-    let by_country = group_states_by_country(states);
 
-    write_country_files(by_country, &country_map, &out_dir)?;
+    let mut by_country: HashMap<u32, Vec<State>> = HashMap::new();
+    load_states(&raw_dir, |state| insert_state(&mut by_country, state))?;
+    let load_elapsed = load_start.elapsed();
+
+    let validate_start = Instant::now();
+    let coordinate_report = validate_coordinates(&mut by_country, out_of_range_policy)?;
+    let validate_elapsed = validate_start.elapsed();
+    println!(
+        "📐 Coordinate validation: {} failed to parse, {} out of range",
+        coordinate_report.parse_failures(),
+        coordinate_report.out_of_range()
+    );
+    coordinate_report.log_issues();
+
+    if csv_enabled {
+        fs::create_dir_all(&csv_dir)?;
+        write_csv_export(&by_country, &country_map, &csv_dir, csv_per_country)?;
+    }
+
+    if let Some(query) = lookup_query {
+        let index = city_index::CityIndex::build(&by_country, &country_map);
+        let hits = index.lookup(query, lookup_country.map(String::as_str));
+        println!("🔎 {} match(es) for {query:?}", hits.len());
+        for hit in hits {
+            println!(
+                "  - {} ({}, {}) at ({:?}, {:?})",
+                hit.city_name, hit.state_name, hit.country_code, hit.latitude, hit.longitude
+            );
+        }
+    }
+
+    let write_start = Instant::now();
+    let summary = write_country_files(
+        by_country,
+        &country_map,
+        &out_dir,
+        format,
+        use_iso3_filename,
+    )?;
+    let write_elapsed = write_start.elapsed();
+
+    let total_elapsed = total_start.elapsed();
+    println!(
+        "⏱️  Dissolved in {total_elapsed:?} (load {load_elapsed:?}, validate {validate_elapsed:?}, write {write_elapsed:?})"
+    );
+    println!(
+        "📦 {} files written, {} countries skipped",
+        summary.files_written,
+        summary.countries_skipped.len()
+    );
 
     println!("✅ Processing complete!");
     Ok(())
@@ -166,7 +850,7 @@ mod tests {
     #[test]
     fn test_country_parsing() {
         let json = r#"[
-            {"id": 1, "iso2": "US"},
+            {"id": 1, "iso2": "US", "iso3": "USA", "numeric_code": "840"},
             {"id": 2, "iso2": "CA"}
         ]"#;
 
@@ -174,8 +858,26 @@ mod tests {
         assert_eq!(countries.len(), 2);
         assert_eq!(countries[0].id, 1);
         assert_eq!(countries[0].code, "US");
+        assert_eq!(countries[0].iso3, Some("USA".to_string()));
+        assert_eq!(countries[0].numeric_code, Some("840".to_string()));
         assert_eq!(countries[1].id, 2);
         assert_eq!(countries[1].code, "CA");
+        assert_eq!(countries[1].iso3, None);
+    }
+
+    #[test]
+    fn test_subdivision_code() {
+        let country = CountryInfo {
+            iso2: "US".to_string(),
+            iso3: Some("USA".to_string()),
+            numeric_code: Some("840".to_string()),
+        };
+
+        assert_eq!(
+            country.subdivision_code(&Some("CA".to_string())),
+            Some("US-CA".to_string())
+        );
+        assert_eq!(country.subdivision_code(&None), None);
     }
 
     #[test]
@@ -209,44 +911,6 @@ mod tests {
         assert_eq!(states[0].cities[0].name, "Los Angeles");
     }
 
-    #[test]
-    fn test_group_states_by_country() {
-        let states = vec![
-            State {
-                id: 1,
-                country_id: 1,
-                name: "California".to_string(),
-                state_code: Some("CA".to_string()),
-                latitude: None,
-                longitude: None,
-                cities: vec![],
-            },
-            State {
-                id: 2,
-                country_id: 1,
-                name: "New York".to_string(),
-                state_code: Some("NY".to_string()),
-                latitude: None,
-                longitude: None,
-                cities: vec![],
-            },
-            State {
-                id: 3,
-                country_id: 2,
-                name: "Ontario".to_string(),
-                state_code: Some("ON".to_string()),
-                latitude: None,
-                longitude: None,
-                cities: vec![],
-            },
-        ];
-
-        let grouped = group_states_by_country(states);
-        assert_eq!(grouped.len(), 2);
-        assert_eq!(grouped.get(&1).unwrap().len(), 2);
-        assert_eq!(grouped.get(&2).unwrap().len(), 1);
-    }
-
     #[test]
     fn test_validate_file_size() {
         let dir = tempdir().unwrap();
@@ -269,4 +933,226 @@ mod tests {
         let filename = format!("{country_id}_{code}.json", country_id = 999, code = "XX");
         assert_eq!(filename, "999_XX.json");
     }
+
+    #[test]
+    fn test_parse_format_flag() {
+        let args = vec![
+            "bin".to_string(),
+            "--format".to_string(),
+            "geojson".to_string(),
+        ];
+        assert_eq!(parse_format_flag(&args), OutputFormat::GeoJson);
+
+        let args = vec!["bin".to_string()];
+        assert_eq!(parse_format_flag(&args), OutputFormat::Json);
+
+        let args = vec![
+            "bin".to_string(),
+            "--format".to_string(),
+            "bogus".to_string(),
+        ];
+        assert_eq!(parse_format_flag(&args), OutputFormat::Json);
+    }
+
+    fn sample_state() -> State {
+        State {
+            id: 1,
+            country_id: 1,
+            name: "California".to_string(),
+            state_code: Some("CA".to_string()),
+            iso_3166_2: None,
+            latitude: Some("36.7783".to_string()),
+            longitude: Some("-119.4179".to_string()),
+            parsed_latitude: None,
+            parsed_longitude: None,
+            cities: vec![
+                City {
+                    id: 1,
+                    name: "Los Angeles".to_string(),
+                    latitude: Some("34.0522".to_string()),
+                    longitude: Some("-118.2437".to_string()),
+                    parsed_latitude: None,
+                    parsed_longitude: None,
+                },
+                City {
+                    id: 2,
+                    name: "Nowhere".to_string(),
+                    latitude: None,
+                    longitude: Some("-118.2437".to_string()),
+                    parsed_latitude: None,
+                    parsed_longitude: None,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_state_to_features() {
+        let mut by_country = HashMap::new();
+        by_country.insert(1, vec![sample_state()]);
+        validate_coordinates(&mut by_country, OutOfRangePolicy::Warn).unwrap();
+
+        let features = state_to_features(&by_country[&1][0]);
+        // The state has cities, so only the city with valid coordinates
+        // gets a feature; the state's own point is skipped and the city
+        // missing a latitude is skipped too.
+        assert_eq!(features.len(), 1);
+        assert_eq!(features[0].geometry.coordinates, [-118.2437, 34.0522]);
+    }
+
+    #[test]
+    fn test_state_to_features_falls_back_to_state_point_when_childless() {
+        let mut state = sample_state();
+        state.cities.clear();
+
+        let mut by_country = HashMap::new();
+        by_country.insert(1, vec![state]);
+        validate_coordinates(&mut by_country, OutOfRangePolicy::Warn).unwrap();
+
+        let features = state_to_features(&by_country[&1][0]);
+        assert_eq!(features.len(), 1);
+        assert_eq!(features[0].geometry.coordinates, [-119.4179, 36.7783]);
+    }
+
+    #[test]
+    fn test_validate_coordinates_reports_parse_failures_and_out_of_range() {
+        let mut state = sample_state();
+        state.latitude = Some("not-a-number".to_string());
+        state.cities[0].latitude = Some("200".to_string()); // out of range
+
+        let mut by_country = HashMap::new();
+        by_country.insert(1, vec![state]);
+
+        let report = validate_coordinates(&mut by_country, OutOfRangePolicy::Warn).unwrap();
+        assert_eq!(report.parse_failures(), 1);
+        assert_eq!(report.out_of_range(), 1);
+
+        let state = &by_country[&1][0];
+        assert_eq!(state.parsed_latitude, None);
+        assert_eq!(state.cities[0].parsed_latitude, None);
+    }
+
+    #[test]
+    fn test_validate_coordinates_hard_errors_on_out_of_range() {
+        let mut state = sample_state();
+        state.latitude = Some("200".to_string());
+
+        let mut by_country = HashMap::new();
+        by_country.insert(1, vec![state]);
+
+        let result = validate_coordinates(&mut by_country, OutOfRangePolicy::Error);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_out_of_range_flag() {
+        let args = vec![
+            "bin".to_string(),
+            "--on-out-of-range".to_string(),
+            "error".to_string(),
+        ];
+        assert_eq!(parse_out_of_range_flag(&args), OutOfRangePolicy::Error);
+
+        let args = vec!["bin".to_string()];
+        assert_eq!(parse_out_of_range_flag(&args), OutOfRangePolicy::Warn);
+    }
+
+    #[test]
+    fn test_state_to_csv_rows() {
+        let mut by_country = HashMap::new();
+        by_country.insert(1, vec![sample_state()]);
+        validate_coordinates(&mut by_country, OutOfRangePolicy::Warn).unwrap();
+
+        let state = &by_country[&1][0];
+        let rows: Vec<CityCsvRow> = state_to_csv_rows(state, "US").collect();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].country_code, "US");
+        assert_eq!(rows[0].state_name, "California");
+        assert_eq!(rows[0].state_code, "CA");
+        assert_eq!(rows[0].city_name, "Los Angeles");
+        assert_eq!(rows[0].latitude, "34.0522");
+        // "Nowhere" has no latitude, so its parsed coordinate is empty.
+        assert_eq!(rows[1].latitude, "");
+    }
+
+    #[test]
+    fn test_write_country_files_reports_success_and_failure() {
+        let dir = tempdir().unwrap();
+        let out_dir = dir.path().join("per-country");
+        fs::create_dir_all(&out_dir).unwrap();
+
+        let mut by_country = HashMap::new();
+        by_country.insert(1, vec![sample_state()]);
+        // Country 2 has no output directory entry on disk, so writing its
+        // file fails and should be recorded rather than aborting the batch.
+        by_country.insert(2, vec![sample_state()]);
+
+        let mut country_map = HashMap::new();
+        country_map.insert(
+            1,
+            CountryInfo {
+                iso2: "US".to_string(),
+                iso3: None,
+                numeric_code: None,
+            },
+        );
+
+        // Force country 2 to fail by pointing it at a path that can't be
+        // created: a file, not a directory, in place of `out_dir`.
+        let bogus_out_dir = dir.path().join("not-a-real-dir");
+        fs::write(&bogus_out_dir, "occupied").unwrap();
+
+        let mut ok_only = HashMap::new();
+        ok_only.insert(1, by_country.remove(&1).unwrap());
+        let summary =
+            write_country_files(ok_only, &country_map, &out_dir, OutputFormat::Json, false)
+                .unwrap();
+        assert_eq!(summary.files_written, 1);
+        assert!(summary.countries_skipped.is_empty());
+        assert!(out_dir.join("1_US.json").exists());
+
+        let mut fail_only = HashMap::new();
+        fail_only.insert(2, by_country.remove(&2).unwrap());
+        let summary = write_country_files(
+            fail_only,
+            &country_map,
+            &bogus_out_dir,
+            OutputFormat::Json,
+            false,
+        )
+        .unwrap();
+        assert_eq!(summary.files_written, 0);
+        assert_eq!(summary.countries_skipped.len(), 1);
+        assert_eq!(summary.countries_skipped[0].0, 2);
+    }
+
+    #[test]
+    fn test_write_one_country_disambiguates_unmapped_countries_with_iso3_filenames() {
+        let dir = tempdir().unwrap();
+        let out_dir = dir.path().join("per-country");
+        fs::create_dir_all(&out_dir).unwrap();
+
+        write_one_country(
+            99,
+            &mut [sample_state()],
+            None,
+            &out_dir,
+            OutputFormat::Json,
+            true,
+        )
+        .unwrap();
+        write_one_country(
+            100,
+            &mut [sample_state()],
+            None,
+            &out_dir,
+            OutputFormat::Json,
+            true,
+        )
+        .unwrap();
+
+        assert!(out_dir.join("99_XX.json").exists());
+        assert!(out_dir.join("100_XX.json").exists());
+    }
 }